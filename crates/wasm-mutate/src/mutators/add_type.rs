@@ -4,6 +4,9 @@ use super::Mutator;
 use crate::Result;
 use rand::Rng;
 use std::iter;
+use wasm_encoder::{
+    CompositeType, FieldType, FuncType, HeapType, RefType, StorageType, SubType,
+};
 
 /// A mutator that appends a new type to the type section.
 ///
@@ -14,73 +17,309 @@ pub struct AddTypeMutator {
     pub(crate) max_results: usize,
 }
 
+/// Everything the random type generators need to know about the module
+/// they're generating a type for.
+struct GenCtx<'a> {
+    gc_enabled: bool,
+    typed_continuations: bool,
+    num_types: u32,
+    /// Indices of the existing function types, used as targets for new
+    /// continuation types.
+    func_indices: &'a [u32],
+}
+
 impl AddTypeMutator {
-    fn random_valtype(&self, rng: &mut impl Rng) -> wasm_encoder::ValType {
-        match rng.gen_range(0..=6) {
+    fn random_valtype(&self, rng: &mut impl Rng, ctx: &GenCtx) -> wasm_encoder::ValType {
+        match rng.gen_range(0..=5) {
             0 => wasm_encoder::ValType::I32,
             1 => wasm_encoder::ValType::I64,
             2 => wasm_encoder::ValType::F32,
             3 => wasm_encoder::ValType::F64,
             4 => wasm_encoder::ValType::V128,
-            5 => wasm_encoder::ValType::ExternRef,
-            6 => wasm_encoder::ValType::FuncRef,
+            5 => wasm_encoder::ValType::Ref(self.random_reftype(rng, ctx)),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Pick a reference type. With the GC proposal enabled this can be any
+    /// nullable-or-not abstract heap type, or a concrete reference to one of
+    /// the module's existing types; otherwise we stick to the legacy
+    /// `funcref`/`externref` shorthands.
+    fn random_reftype(&self, rng: &mut impl Rng, ctx: &GenCtx) -> RefType {
+        if !ctx.gc_enabled {
+            return RefType {
+                nullable: true,
+                heap_type: if rng.gen_bool(0.5) {
+                    HeapType::Func
+                } else {
+                    HeapType::Extern
+                },
+            };
+        }
+
+        RefType {
+            nullable: rng.gen_bool(0.5),
+            heap_type: self.random_heaptype(rng, ctx.num_types),
+        }
+    }
+
+    fn random_heaptype(&self, rng: &mut impl Rng, num_types: u32) -> HeapType {
+        // Occasionally point at one of the module's existing types, when
+        // there are any to point at.
+        if num_types > 0 && rng.gen_ratio(1, 4) {
+            return HeapType::Concrete(rng.gen_range(0..num_types));
+        }
+
+        match rng.gen_range(0..=9) {
+            0 => HeapType::Func,
+            1 => HeapType::Extern,
+            2 => HeapType::Any,
+            3 => HeapType::Eq,
+            4 => HeapType::I31,
+            5 => HeapType::Struct,
+            6 => HeapType::Array,
+            7 => HeapType::None,
+            8 => HeapType::NoFunc,
+            9 => HeapType::NoExtern,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Pick a storage type for a struct or array field: either a full value
+    /// type or, when the GC proposal is enabled, one of the packed `i8`/`i16`
+    /// storage types that are only legal inside GC aggregates.
+    fn random_storage_type(&self, rng: &mut impl Rng, ctx: &GenCtx) -> StorageType {
+        if ctx.gc_enabled && rng.gen_range(0..=3) == 0 {
+            if rng.gen_bool(0.5) {
+                StorageType::I8
+            } else {
+                StorageType::I16
+            }
+        } else {
+            StorageType::Val(self.random_valtype(rng, ctx))
+        }
+    }
+
+    fn random_field_type(&self, rng: &mut impl Rng, ctx: &GenCtx) -> FieldType {
+        FieldType {
+            element_type: self.random_storage_type(rng, ctx),
+            mutable: rng.gen_bool(0.5),
+        }
+    }
+
+    fn random_func_type(&self, rng: &mut impl Rng, ctx: &GenCtx) -> FuncType {
+        let count = rng.gen_range(0..=self.max_params);
+        let params = (0..count)
+            .map(|_| self.random_valtype(rng, ctx))
+            .collect::<Vec<_>>();
+
+        let count = rng.gen_range(0..=self.max_results);
+        let results = (0..count)
+            .map(|_| self.random_valtype(rng, ctx))
+            .collect::<Vec<_>>();
+
+        FuncType::new(params, results)
+    }
+
+    fn random_struct_fields(&self, rng: &mut impl Rng, ctx: &GenCtx) -> Vec<FieldType> {
+        let count = rng.gen_range(0..=self.max_params);
+        (0..count)
+            .map(|_| self.random_field_type(rng, ctx))
+            .collect()
+    }
+
+    /// Pick a composite type kind -- function, or (when the GC proposal is
+    /// enabled) struct or array, or (when the typed-continuations proposal
+    /// is enabled and a function type exists to point at) continuation --
+    /// and generate a random instance of it.
+    ///
+    /// Struct and array types are only ever emitted when `ctx.gc_enabled` is
+    /// set: a non-GC module must not gain GC-only composite types just
+    /// because we felt like adding a type to it.
+    fn random_composite_type(&self, rng: &mut impl Rng, ctx: &GenCtx) -> CompositeType {
+        let can_cont = ctx.typed_continuations && !ctx.func_indices.is_empty();
+        if !ctx.gc_enabled {
+            return if can_cont && rng.gen_bool(0.5) {
+                CompositeType::Cont(ctx.func_indices[rng.gen_range(0..ctx.func_indices.len())])
+            } else {
+                CompositeType::Func(self.random_func_type(rng, ctx))
+            };
+        }
+        let max_kind = if can_cont { 3 } else { 2 };
+        match rng.gen_range(0..=max_kind) {
+            0 => CompositeType::Func(self.random_func_type(rng, ctx)),
+            1 => CompositeType::Struct(self.random_struct_fields(rng, ctx)),
+            2 => CompositeType::Array(self.random_field_type(rng, ctx)),
+            3 => CompositeType::Cont(ctx.func_indices[rng.gen_range(0..ctx.func_indices.len())]),
             _ => unreachable!(),
         }
     }
+
+    /// Optionally pick an existing, non-final, structurally-compatible type
+    /// to serve as a new type's supertype, so that the resulting module
+    /// still validates. A `final` type can never legally be a supertype, so
+    /// those are excluded outright regardless of shape.
+    fn random_supertype(
+        &self,
+        rng: &mut impl Rng,
+        composite_type: &CompositeType,
+        existing_types: &[SubType],
+    ) -> Option<u32> {
+        if !rng.gen_ratio(1, 3) {
+            return None;
+        }
+        let candidates = existing_types
+            .iter()
+            .enumerate()
+            .filter(|(_, t)| {
+                !t.is_final && is_structural_supertype(&t.composite_type, composite_type)
+            })
+            .map(|(i, _)| i as u32)
+            .collect::<Vec<_>>();
+        if candidates.is_empty() {
+            return None;
+        }
+        Some(candidates[rng.gen_range(0..candidates.len())])
+    }
+
+    /// Build a brand new recursion group: a handful of fresh types, each
+    /// optionally declaring a supertype chosen from the types that already
+    /// exist in the module.
+    fn random_rec_group(
+        &self,
+        rng: &mut impl Rng,
+        ctx: &GenCtx,
+        existing_types: &[SubType],
+    ) -> Vec<SubType> {
+        // Multi-member recursion groups and non-final types only exist in
+        // the GC proposal, so without it the group is a single final type.
+        let group_size = if ctx.gc_enabled { rng.gen_range(1..=3) } else { 1 };
+        (0..group_size)
+            .map(|_| {
+                let composite_type = self.random_composite_type(rng, ctx);
+                let supertype_idx = self.random_supertype(rng, &composite_type, existing_types);
+                SubType {
+                    is_final: !ctx.gc_enabled || rng.gen_bool(0.7),
+                    supertype_idx,
+                    composite_type,
+                }
+            })
+            .collect()
+    }
+
+    /// Whether this mutator has some kind of type it can add that isn't a
+    /// continuation type. Currently always true: a plain function type
+    /// stays on the menu in `random_composite_type` no matter what
+    /// `gc_enabled` is set to, so continuation types are never the *only*
+    /// kind of type a call could add. As a result `can_mutate`'s answer does
+    /// not depend on `typed_continuations`; that flag only decides, inside
+    /// `mutate`, whether continuation types can be generated.
+    fn can_add_type_without_continuations(&self) -> bool {
+        true
+    }
+}
+
+/// A conservative approximation of the GC proposal's structural subtyping
+/// rules, used to decide whether `candidate` could legally be declared as
+/// `sub`'s supertype: function types must match exactly, array types must
+/// match exactly, and struct types may extend a compatible prefix with extra
+/// trailing fields. Anything else (including continuation types, which
+/// aren't part of the GC subtyping lattice) is never compatible.
+fn is_structural_supertype(candidate: &CompositeType, sub: &CompositeType) -> bool {
+    match (candidate, sub) {
+        (CompositeType::Func(a), CompositeType::Func(b)) => a == b,
+        (CompositeType::Array(a), CompositeType::Array(b)) => a == b,
+        (CompositeType::Struct(a), CompositeType::Struct(b)) => {
+            b.len() >= a.len() && a.iter().zip(b.iter()).all(|(x, y)| x == y)
+        }
+        _ => false,
+    }
 }
 
 impl Mutator for AddTypeMutator {
     fn can_mutate(&self, config: &crate::WasmMutate) -> bool {
-        !config.reduce
+        !config.reduce && (config.typed_continuations || self.can_add_type_without_continuations())
     }
 
     fn mutate<'a>(
         self,
         config: &'a mut crate::WasmMutate,
     ) -> crate::Result<Box<dyn Iterator<Item = crate::Result<wasm_encoder::Module>> + 'a>> {
-        let count = config.rng().gen_range(0..=self.max_params);
-        let mut params = Vec::with_capacity(count);
-        for _ in 0..count {
-            params.push(self.random_valtype(config.rng()));
+        let gc_enabled = config.gc_enabled;
+        let typed_continuations = config.typed_continuations;
+        let mut existing_types = match config.info().get_type_section() {
+            Some(s) => existing_types_of(s.data)?,
+            None => Vec::new(),
+        };
+
+        // A continuation type always references a function type. If we
+        // want the option of generating one but the module doesn't have a
+        // function type yet, synthesize a trivial one up front so there's
+        // always something to point at. It is dropped again below when no
+        // new continuation type ends up using it.
+        let mut group = Vec::new();
+        let mut synthesized_func = false;
+        if typed_continuations
+            && !existing_types
+                .iter()
+                .any(|t| matches!(t.composite_type, CompositeType::Func(_)))
+        {
+            let trivial_func = SubType {
+                is_final: true,
+                supertype_idx: None,
+                composite_type: CompositeType::Func(FuncType::new(vec![], vec![])),
+            };
+            group.push(trivial_func.clone());
+            existing_types.push(trivial_func);
+            synthesized_func = true;
         }
 
-        let count = config.rng().gen_range(0..=self.max_results);
-        let mut results = Vec::with_capacity(count);
-        for _ in 0..count {
-            results.push(self.random_valtype(config.rng()));
+        let func_indices = existing_types
+            .iter()
+            .enumerate()
+            .filter(|(_, t)| matches!(t.composite_type, CompositeType::Func(_)))
+            .map(|(i, _)| i as u32)
+            .collect::<Vec<_>>();
+        let ctx = GenCtx {
+            gc_enabled,
+            typed_continuations,
+            // Concrete heap types never point at the synthesized function
+            // type, so only continuation types can depend on it.
+            num_types: existing_types.len() as u32 - synthesized_func as u32,
+            func_indices: &func_indices,
+        };
+        let new_types = self.random_rec_group(config.rng(), &ctx, &existing_types);
+        if synthesized_func
+            && !new_types
+                .iter()
+                .any(|t| matches!(t.composite_type, CompositeType::Cont(_)))
+        {
+            group.clear();
         }
+        group.extend(new_types);
 
         let mut types = wasm_encoder::TypeSection::new();
         if let Some(old_types) = config.info().get_type_section() {
-            // Copy the existing types section over into the encoder.
-            let mut reader = wasmparser::TypeSectionReader::new(old_types.data, 0)?;
-            for _ in 0..reader.get_count() {
-                let ty = reader.read()?;
-                match ty {
-                    wasmparser::Type::Func(ty) => {
-                        let params = ty
-                            .params
-                            .iter()
-                            .map(translate_type)
-                            .collect::<Result<Vec<_>, _>>()?;
-                        let results = ty
-                            .returns
-                            .iter()
-                            .map(translate_type)
-                            .collect::<Result<Vec<_>, _>>()?;
-                        types.function(params, results);
-                    }
-                    wasmparser::Type::Cont(_) => unimplemented!(),
-                }
+            // Copy the existing types section over into the encoder one
+            // recursion group at a time, so that we don't flatten away
+            // group boundaries or the subtyping metadata they carry.
+            let reader = wasmparser::TypeSectionReader::new(old_types.data, 0)?;
+            for rec_group in reader {
+                let rec_group = rec_group?;
+                let subtypes = rec_group
+                    .types()
+                    .map(translate_subtype)
+                    .collect::<Result<Vec<_>, _>>()?;
+                push_group(&mut types, subtypes);
             }
-            // And then add our new type.
-            types.function(params, results);
+            // And then add our new recursion group.
+            push_group(&mut types, group);
             let types_section_index = config.info().types.unwrap();
             Ok(Box::new(iter::once(Ok(config
                 .info()
                 .replace_section(types_section_index, &types)))))
         } else {
-            types.function(params, results);
+            push_group(&mut types, group);
             Ok(Box::new(iter::once(Ok(config
                 .info()
                 .insert_section(0, &types)))))
@@ -88,7 +327,83 @@ impl Mutator for AddTypeMutator {
     }
 }
 
-fn translate_type(ty: &wasmparser::ValType) -> Result<wasm_encoder::ValType> {
+/// Emit a recursion group: a single member is emitted as a standalone
+/// subtype (so that non-GC consumers see exactly what they did before),
+/// while two or more members are wrapped in an explicit `rec` group.
+fn push_group(types: &mut wasm_encoder::TypeSection, subtypes: Vec<SubType>) {
+    if let [ty] = &subtypes[..] {
+        types.subtype(ty);
+    } else {
+        types.rec(subtypes);
+    }
+}
+
+/// Walk the type section's recursion groups and translate each subtype into
+/// its `wasm_encoder` form, so a later pass can pick structurally
+/// compatible, non-final supertypes without re-parsing the section.
+fn existing_types_of(data: &[u8]) -> Result<Vec<SubType>> {
+    let reader = wasmparser::TypeSectionReader::new(data, 0)?;
+    let mut types = Vec::new();
+    for rec_group in reader {
+        for ty in rec_group?.types() {
+            types.push(translate_subtype(ty)?);
+        }
+    }
+    Ok(types)
+}
+
+fn translate_subtype(ty: &wasmparser::SubType) -> Result<SubType> {
+    Ok(SubType {
+        is_final: ty.is_final,
+        supertype_idx: ty.supertype_idx,
+        composite_type: translate_composite_type(&ty.composite_type)?,
+    })
+}
+
+fn translate_composite_type(ty: &wasmparser::CompositeType) -> Result<CompositeType> {
+    Ok(match ty {
+        wasmparser::CompositeType::Func(ty) => {
+            let params = ty
+                .params
+                .iter()
+                .map(translate_valtype)
+                .collect::<Result<Vec<_>, _>>()?;
+            let results = ty
+                .returns
+                .iter()
+                .map(translate_valtype)
+                .collect::<Result<Vec<_>, _>>()?;
+            CompositeType::Func(FuncType::new(params, results))
+        }
+        wasmparser::CompositeType::Struct(ty) => {
+            let fields = ty
+                .fields
+                .iter()
+                .map(translate_field_type)
+                .collect::<Result<Vec<_>, _>>()?;
+            CompositeType::Struct(fields)
+        }
+        wasmparser::CompositeType::Array(ty) => CompositeType::Array(translate_field_type(&ty.0)?),
+        wasmparser::CompositeType::Cont(func_type_idx) => CompositeType::Cont(*func_type_idx),
+    })
+}
+
+fn translate_field_type(ty: &wasmparser::FieldType) -> Result<FieldType> {
+    Ok(FieldType {
+        element_type: translate_storage_type(&ty.element_type)?,
+        mutable: ty.mutable,
+    })
+}
+
+fn translate_storage_type(ty: &wasmparser::StorageType) -> Result<StorageType> {
+    Ok(match ty {
+        wasmparser::StorageType::I8 => StorageType::I8,
+        wasmparser::StorageType::I16 => StorageType::I16,
+        wasmparser::StorageType::Val(ty) => StorageType::Val(translate_valtype(ty)?),
+    })
+}
+
+fn translate_valtype(ty: &wasmparser::ValType) -> Result<wasm_encoder::ValType> {
     Ok(match ty {
         wasmparser::ValType::I32 => wasm_encoder::ValType::I32,
         wasmparser::ValType::I64 => wasm_encoder::ValType::I64,
@@ -101,10 +416,25 @@ fn translate_type(ty: &wasmparser::ValType) -> Result<wasm_encoder::ValType> {
 }
 
 fn translate_ref_type(rt: &wasmparser::RefType) -> Result<wasm_encoder::ValType> {
-    Ok(match *rt {
-        wasmparser::FUNC_REF => wasm_encoder::ValType::FuncRef,
-        wasmparser::EXTERN_REF => wasm_encoder::ValType::ExternRef,
-        _ => unimplemented!(),
+    Ok(wasm_encoder::ValType::Ref(RefType {
+        nullable: rt.is_nullable(),
+        heap_type: translate_heap_type(&rt.heap_type())?,
+    }))
+}
+
+fn translate_heap_type(ty: &wasmparser::HeapType) -> Result<HeapType> {
+    Ok(match ty {
+        wasmparser::HeapType::Func => HeapType::Func,
+        wasmparser::HeapType::Extern => HeapType::Extern,
+        wasmparser::HeapType::Any => HeapType::Any,
+        wasmparser::HeapType::Eq => HeapType::Eq,
+        wasmparser::HeapType::I31 => HeapType::I31,
+        wasmparser::HeapType::Struct => HeapType::Struct,
+        wasmparser::HeapType::Array => HeapType::Array,
+        wasmparser::HeapType::None => HeapType::None,
+        wasmparser::HeapType::NoFunc => HeapType::NoFunc,
+        wasmparser::HeapType::NoExtern => HeapType::NoExtern,
+        wasmparser::HeapType::Indexed(idx) => HeapType::Concrete(*idx),
     })
 }
 
@@ -173,4 +503,155 @@ mod tests {
             "#,
         );
     }
+
+    #[test]
+    fn round_trip_cont_type() {
+        crate::mutators::match_mutation(
+            r#"
+                (module
+                    (type (;0;) (func))
+                    (type (;1;) (cont 0))
+                )
+            "#,
+            AddTypeMutator {
+                max_params: 0,
+                max_results: 0,
+            },
+            r#"
+                (module
+                    (type (;0;) (func))
+                    (type (;1;) (cont 0))
+                    (type (;2;) (cont 0))
+                )
+            "#,
+        );
+    }
+
+    #[test]
+    fn add_cont_type_referencing_existing_func() {
+        crate::mutators::match_mutation(
+            r#"
+                (module
+                    (type (;0;) (func))
+                )
+            "#,
+            AddTypeMutator {
+                max_params: 0,
+                max_results: 0,
+            },
+            r#"
+                (module
+                    (type (;0;) (func))
+                    (type (;1;) (cont 0))
+                )
+            "#,
+        );
+    }
+
+    #[test]
+    fn round_trip_struct_type() {
+        crate::mutators::match_mutation(
+            r#"
+                (module
+                    (type (;0;) (struct (field i32) (field i64)))
+                )
+            "#,
+            AddTypeMutator {
+                max_params: 1,
+                max_results: 0,
+            },
+            r#"
+                (module
+                    (type (;0;) (struct (field i32) (field i64)))
+                    (type (;1;) (struct (field i32)))
+                )
+            "#,
+        );
+    }
+
+    #[test]
+    fn round_trip_array_type() {
+        crate::mutators::match_mutation(
+            r#"
+                (module
+                    (type (;0;) (array i32))
+                )
+            "#,
+            AddTypeMutator {
+                max_params: 0,
+                max_results: 0,
+            },
+            r#"
+                (module
+                    (type (;0;) (array i32))
+                    (type (;1;) (array i64))
+                )
+            "#,
+        );
+    }
+
+    #[test]
+    fn round_trip_rec_group_with_subtyping() {
+        crate::mutators::match_mutation(
+            r#"
+                (module
+                    (type (;0;) (sub (struct (field i32))))
+                )
+            "#,
+            AddTypeMutator {
+                max_params: 2,
+                max_results: 0,
+            },
+            r#"
+                (module
+                    (type (;0;) (sub (struct (field i32))))
+                    (type (;1;) (sub 0 (struct (field i32) (field i64))))
+                )
+            "#,
+        );
+    }
+
+    #[test]
+    fn round_trip_concrete_ref_type() {
+        crate::mutators::match_mutation(
+            r#"
+                (module
+                    (type (;0;) (func))
+                    (type (;1;) (struct (field (ref null 0))))
+                )
+            "#,
+            AddTypeMutator {
+                max_params: 1,
+                max_results: 0,
+            },
+            r#"
+                (module
+                    (type (;0;) (func))
+                    (type (;1;) (struct (field (ref null 0))))
+                    (type (;2;) (struct (field (ref null 0))))
+                )
+            "#,
+        );
+    }
+
+    #[test]
+    fn round_trip_abstract_heap_ref_type() {
+        crate::mutators::match_mutation(
+            r#"
+                (module
+                    (type (;0;) (struct (field (ref null any))))
+                )
+            "#,
+            AddTypeMutator {
+                max_params: 1,
+                max_results: 0,
+            },
+            r#"
+                (module
+                    (type (;0;) (struct (field (ref null any))))
+                    (type (;1;) (struct (field (ref null any))))
+                )
+            "#,
+        );
+    }
 }